@@ -1,20 +1,604 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use parking_lot::RwLock;
-use socket2::{Domain, Protocol, Type};
+use parking_lot::{Mutex, RwLock};
+use socket2::{Domain, Protocol, SockRef, Type};
 use std::io::{Read, Write};
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::str::FromStr;
 
+use base64::Engine as _;
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+
 use crate::device::{AllowedIps, Error, ProxyConfig};
 use crate::noise::{Tunn, TunnResult};
 
+/// Abstracts over the different ways a `Peer`'s packets actually reach the network: a
+/// direct UDP socket, a SOCKS5 UDP ASSOCIATE relay, or a proxy that tunnels packets over
+/// a byte stream (WebSocket, HTTP CONNECT). `connect_endpoint` picks the implementation
+/// based on the configured `ProxyConfig`; everything above this layer only ever calls
+/// `send_packet`/`recv_packet`.
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    fn send_packet(&self, packet: &[u8]) -> Result<(), Error>;
+    fn recv_packet(&self, buf: &mut [u8]) -> Result<usize, Error>;
+    fn as_raw_fd(&self) -> RawFd;
+    /// Tears down the underlying connection(s). `shutdown_endpoint` and `set_endpoint`
+    /// call it once before dropping the transport, so proxy control connections get an
+    /// explicit shutdown rather than just being silently closed. Dropping a `Peer` drops
+    /// its transport without calling it.
+    fn shutdown(&self);
+}
+
+#[derive(Debug)]
+struct UdpTransport(socket2::Socket);
+
+impl Transport for UdpTransport {
+    fn send_packet(&self, packet: &[u8]) -> Result<(), Error> {
+        self.0.send(packet)?;
+        Ok(())
+    }
+
+    fn recv_packet(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.0.recv(buf)?)
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
+    fn shutdown(&self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+/// SOCKS5 UDP ASSOCIATE per RFC 1928 section 7: every datagram to/from the relay carries
+/// a `RSV | FRAG | ATYP | DST.ADDR | DST.PORT` header addressed to `target`, and the TCP
+/// control connection must stay open for the association to remain valid.
+#[derive(Debug)]
+struct Socks5UdpTransport {
+    socket: socket2::Socket,
+    control: Mutex<TcpStream>,
+    target: SocketAddr,
+}
+
+impl Transport for Socks5UdpTransport {
+    fn send_packet(&self, packet: &[u8]) -> Result<(), Error> {
+        let mut framed = socks5_udp_header(self.target);
+        framed.extend_from_slice(packet);
+        self.socket.send(&framed)?;
+        Ok(())
+    }
+
+    fn recv_packet(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut datagram = [0u8; 65536];
+        let n = self.socket.recv(&mut datagram)?;
+        let payload = strip_socks5_udp_header(&datagram[..n])?;
+        if payload.len() > buf.len() {
+            return Err(Error::Connect(
+                "SOCKS5 UDP datagram larger than buffer".to_owned(),
+            ));
+        }
+        buf[..payload.len()].copy_from_slice(payload);
+        Ok(payload.len())
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    fn shutdown(&self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+        // Closing the control connection tears down the SOCKS5 UDP association
+        let _ = self.control.lock().shutdown(Shutdown::Both);
+    }
+}
+
+/// A `TcpStream` plus whatever bytes have been read off it but not yet consumed into a
+/// full frame, and whatever bytes are still queued to be written. The stream is always
+/// non-blocking, so both directions can be interrupted partway through a frame:
+///
+/// - `fill` accumulates everything currently available into `read_buf` without losing
+///   bytes a caller already consumed partway through a frame, which a bare `read_exact`
+///   on a non-blocking fd would do (it returns `WouldBlock` mid-read and the bytes it
+///   already took are gone).
+/// - `queue_write` appends to `write_buf` and flushes as much as the socket will accept
+///   right now, instead of `write_all`, which on `WouldBlock` can leave half a frame on
+///   the wire with no way to send the rest without corrupting the framing. Because later
+///   writes are always appended after whatever's still pending, the bytes that do reach
+///   the socket are never reordered or interleaved with a later frame's header.
+#[derive(Debug)]
+struct BufferedStream {
+    stream: TcpStream,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl BufferedStream {
+    fn new(stream: TcpStream) -> Self {
+        BufferedStream {
+            stream,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Reads everything currently available without blocking. Returns whether any bytes
+    /// were read; `WouldBlock` is not an error here; it just means there's nothing more
+    /// to read right now.
+    fn fill(&mut self) -> Result<bool, Error> {
+        let mut chunk = [0u8; 4096];
+        let mut read_any = false;
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(Error::Connect("Proxy connection closed".to_owned()));
+                }
+                Ok(n) => {
+                    self.read_buf.extend_from_slice(&chunk[..n]);
+                    read_any = true;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(read_any),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Appends `bytes` to the pending write queue, behind anything already queued, then
+    /// flushes as much as the socket currently accepts.
+    fn queue_write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.write_buf.extend_from_slice(bytes);
+        self.flush_writes()
+    }
+
+    /// Writes as much of the pending queue as the socket will accept without blocking.
+    /// Whatever doesn't fit stays queued for the next call.
+    fn flush_writes(&mut self) -> Result<(), Error> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(Error::Connect("Proxy connection closed".to_owned())),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// WireGuard-over-WebSocket: each outgoing packet is sent as a single binary WebSocket
+/// message and each inbound binary message is handed to `Tunn` as a packet. Ping frames
+/// are answered with a pong and otherwise ignored, rather than being handed up as data.
+#[derive(Debug)]
+struct WebSocketTransport {
+    state: Mutex<BufferedStream>,
+}
+
+impl Transport for WebSocketTransport {
+    fn send_packet(&self, packet: &[u8]) -> Result<(), Error> {
+        let frame = encode_ws_frame(0x2, packet);
+        self.state.lock().queue_write(&frame)
+    }
+
+    fn recv_packet(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.state.lock();
+        loop {
+            while let Some((consumed, frame)) = try_parse_ws_frame(&state.read_buf)? {
+                state.read_buf.drain(..consumed);
+                match frame.opcode {
+                    0x2 => {
+                        if frame.payload.len() > buf.len() {
+                            return Err(Error::Connect(
+                                "WebSocket message larger than buffer".to_owned(),
+                            ));
+                        }
+                        buf[..frame.payload.len()].copy_from_slice(&frame.payload);
+                        return Ok(frame.payload.len());
+                    }
+                    0x9 => {
+                        // Ping: answer with a pong carrying the same payload and keep
+                        // looking for a data frame.
+                        let pong = encode_ws_frame(0xa, &frame.payload);
+                        state.queue_write(&pong)?;
+                    }
+                    0xa => {
+                        // Pong: nothing to do, just discard.
+                    }
+                    0x8 => {
+                        return Err(Error::Connect(
+                            "WebSocket connection closed by proxy".to_owned(),
+                        ));
+                    }
+                    opcode => {
+                        return Err(Error::Connect(format!(
+                            "Unsupported WebSocket opcode: {opcode:#04x}"
+                        )));
+                    }
+                }
+            }
+
+            if !state.fill()? {
+                return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+            }
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.state.lock().stream.as_raw_fd()
+    }
+
+    fn shutdown(&self) {
+        let _ = self.state.lock().stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Length-prefix framing for tunneling WireGuard over a plain TCP byte stream (HTTP
+/// CONNECT, SOCKS4): each packet is sent as a 2-byte big-endian length followed by the
+/// packet bytes.
+#[derive(Debug)]
+struct TcpFramedTransport {
+    state: Mutex<BufferedStream>,
+}
+
+impl Transport for TcpFramedTransport {
+    fn send_packet(&self, packet: &[u8]) -> Result<(), Error> {
+        if packet.len() > u16::MAX as usize {
+            return Err(Error::Connect(
+                "Packet too large to frame over TCP".to_owned(),
+            ));
+        }
+        let mut framed = (packet.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(packet);
+        self.state.lock().queue_write(&framed)
+    }
+
+    fn recv_packet(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(len) = parse_framed_tcp_len(&state.read_buf) {
+                if state.read_buf.len() >= 2 + len {
+                    if len > buf.len() {
+                        return Err(Error::Connect(
+                            "Framed TCP packet larger than buffer".to_owned(),
+                        ));
+                    }
+                    buf[..len].copy_from_slice(&state.read_buf[2..2 + len]);
+                    state.read_buf.drain(..2 + len);
+                    return Ok(len);
+                }
+            }
+
+            if !state.fill()? {
+                return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+            }
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.state.lock().stream.as_raw_fd()
+    }
+
+    fn shutdown(&self) {
+        let _ = self.state.lock().stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Reads the 2-byte big-endian length prefix off the front of `buf`, if present.
+fn parse_framed_tcp_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 2 {
+        return None;
+    }
+    Some(u16::from_be_bytes([buf[0], buf[1]]) as usize)
+}
+
+fn random_bytes4() -> [u8; 4] {
+    let mut bytes = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn random_bytes16() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Frames `payload` as a single, masked WebSocket message (RFC 6455 section 5) with the
+/// given opcode. Client-to-server frames must be masked even though the mask itself is
+/// not a security boundary.
+fn encode_ws_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode); // FIN | opcode
+
+    let len = payload.len();
+    if len < 126 {
+        frame.push(0x80 | len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    let mask = random_bytes4();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+struct WsFrame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Attempts to parse a single WebSocket frame out of the front of `buf`. Returns
+/// `Ok(None)` if `buf` doesn't yet hold a complete frame (the caller should read more
+/// and try again), or `Ok(Some((consumed, frame)))` with the number of bytes the frame
+/// occupied so the caller can drain them.
+fn try_parse_ws_frame(buf: &[u8]) -> Result<Option<(usize, WsFrame)>, Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let mut len = (buf[1] & 0x7f) as u64;
+    let mut offset = 2;
+
+    if len == 126 {
+        if buf.len() < offset + 2 {
+            return Ok(None);
+        }
+        len = u16::from_be_bytes([buf[offset], buf[offset + 1]]) as u64;
+        offset += 2;
+    } else if len == 127 {
+        if buf.len() < offset + 8 {
+            return Ok(None);
+        }
+        let mut ext = [0u8; 8];
+        ext.copy_from_slice(&buf[offset..offset + 8]);
+        len = u64::from_be_bytes(ext);
+        offset += 8;
+    }
+
+    let mask = if masked {
+        if buf.len() < offset + 4 {
+            return Ok(None);
+        }
+        let mask = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let len = len as usize;
+    if buf.len() < offset + len {
+        return Ok(None);
+    }
+
+    if !fin {
+        return Err(Error::Connect(
+            "Fragmented WebSocket messages are not supported".to_owned(),
+        ));
+    }
+
+    let mut payload = buf[offset..offset + len].to_vec();
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+
+    Ok(Some((offset + len, WsFrame { opcode, payload })))
+}
+
+/// Reads raw bytes off `stream` up to and including the blank line that terminates an
+/// HTTP response's headers, leaving any bytes that follow (e.g. the first WebSocket
+/// frame) unread on the stream.
+fn read_http_response_headers(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        stream.read_exact(&mut byte)?;
+        buf.push(byte[0]);
+        if buf.len() >= 4 && buf[buf.len() - 4..] == *b"\r\n\r\n" {
+            return Ok(buf);
+        }
+        if buf.len() > 8192 {
+            return Err(Error::Connect("HTTP response headers too large".to_owned()));
+        }
+    }
+}
+
+/// Performs the HTTP Upgrade handshake to a WebSocket proxy (RFC 6455 section 1.3) over
+/// an already-connected `stream`.
+fn websocket_handshake(stream: &mut TcpStream, host: &str, path: &str) -> Result<(), Error> {
+    let key = base64::engine::general_purpose::STANDARD.encode(random_bytes16());
+
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let response = read_http_response_headers(stream)?;
+    let response = String::from_utf8_lossy(&response);
+    let mut lines = response.split("\r\n");
+
+    let status = lines.next().unwrap_or_default();
+    if !status.contains("101") {
+        return Err(Error::Connect(format!(
+            "WebSocket upgrade rejected: {status}"
+        )));
+    }
+
+    let accept = lines
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("Sec-WebSocket-Accept")
+                .then(|| value.trim().to_owned())
+        })
+        .ok_or_else(|| Error::Connect("Missing Sec-WebSocket-Accept header".to_owned()))?;
+
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let expected = base64::engine::general_purpose::STANDARD.encode(hasher.finalize());
+
+    if accept != expected {
+        return Err(Error::Connect("Sec-WebSocket-Accept mismatch".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Builds the CONNECT request line and headers for `target`, including an HTTP Basic
+/// `Proxy-Authorization` header when credentials are supplied.
+fn http_connect_request(target: SocketAddr, username: Option<&str>, password: Option<&str>) -> String {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let (Some(username), Some(password)) = (username, password) {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Performs the HTTP CONNECT handshake to `target` through an already-connected proxy
+/// `stream`, optionally authenticating with HTTP Basic credentials.
+fn http_connect_handshake(
+    stream: &mut TcpStream,
+    target: SocketAddr,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<(), Error> {
+    let request = http_connect_request(target, username, password);
+    stream.write_all(request.as_bytes())?;
+
+    let response = read_http_response_headers(stream)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split("\r\n").next().unwrap_or_default();
+    if !status.contains("200") {
+        return Err(Error::Connect(format!("HTTP CONNECT rejected: {status}")));
+    }
+
+    Ok(())
+}
+
+/// Builds a SOCKS4 CONNECT request for `target`. SOCKS4 has no IPv6 representation at
+/// all — not even via the SOCKS4a hostname extension, which only replaces the
+/// destination *address*, and still requires a valid IPv4 placeholder plus a hostname a
+/// real DNS resolver can look up. An IPv6 literal isn't such a hostname, so an IPv6
+/// target is rejected outright rather than producing a request the relay will refuse.
+fn socks4_connect_request(target: SocketAddr, userid: &str) -> Result<Vec<u8>, Error> {
+    let v4 = match target {
+        SocketAddr::V4(v4) => v4,
+        SocketAddr::V6(_) => {
+            return Err(Error::Connect(
+                "SOCKS4 does not support IPv6 endpoints".to_owned(),
+            ))
+        }
+    };
+
+    let mut request = vec![0x04, 0x01]; // VER, CMD(CONNECT)
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request.extend_from_slice(&v4.ip().octets());
+    request.extend_from_slice(userid.as_bytes());
+    request.push(0x00);
+
+    Ok(request)
+}
+
+/// Performs a SOCKS4 CONNECT handshake to `target` over an already-connected proxy
+/// `stream`.
+fn socks4_connect_handshake(
+    stream: &mut TcpStream,
+    target: SocketAddr,
+    userid: Option<&str>,
+) -> Result<(), Error> {
+    let request = socks4_connect_request(target, userid.unwrap_or(""))?;
+    stream.write_all(&request)?;
+
+    let mut response = [0u8; 8];
+    stream.read_exact(&mut response)?;
+    if response[0] != 0x00 {
+        return Err(Error::Connect("Malformed SOCKS4 response".to_owned()));
+    }
+    if response[1] != 0x5a {
+        return Err(Error::Connect(format!(
+            "SOCKS4 CONNECT failed: CD={:#04x}",
+            response[1]
+        )));
+    }
+
+    Ok(())
+}
+
+fn socks5_udp_header(dst: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0x00, 0x00, 0x00]; // RSV(2) | FRAG(1)
+    match dst {
+        SocketAddr::V4(v4) => {
+            header.push(0x01); // ATYP: IPv4
+            header.extend_from_slice(&v4.ip().octets());
+            header.extend_from_slice(&v4.port().to_be_bytes());
+        }
+        SocketAddr::V6(v6) => {
+            header.push(0x04); // ATYP: IPv6
+            header.extend_from_slice(&v6.ip().octets());
+            header.extend_from_slice(&v6.port().to_be_bytes());
+        }
+    }
+    header
+}
+
+fn strip_socks5_udp_header(datagram: &[u8]) -> Result<&[u8], Error> {
+    if datagram.len() < 4 {
+        return Err(Error::Connect("Truncated SOCKS5 UDP datagram".to_owned()));
+    }
+    if datagram[2] != 0x00 {
+        return Err(Error::Connect(
+            "Fragmented SOCKS5 UDP datagrams are not supported".to_owned(),
+        ));
+    }
+    let header_len = match datagram[3] {
+        0x01 => 4 + 4 + 2,
+        0x04 => 4 + 16 + 2,
+        _ => {
+            return Err(Error::Connect(
+                "Unsupported address type in SOCKS5 UDP datagram".to_owned(),
+            ))
+        }
+    };
+    if datagram.len() < header_len {
+        return Err(Error::Connect("Truncated SOCKS5 UDP datagram".to_owned()));
+    }
+    Ok(&datagram[header_len..])
+}
+
 #[derive(Default, Debug)]
 pub struct Endpoint {
     pub addr: Option<SocketAddr>,
-    pub conn: Option<socket2::Socket>,
+    /// The active transport, if connected. Every packet send/receive routes through
+    /// this rather than a raw socket, so proxy encapsulation happens uniformly
+    /// regardless of which transport is in use.
+    pub transport: Option<Box<dyn Transport>>,
 }
 
 pub struct Peer {
@@ -64,7 +648,7 @@ impl Peer {
             index,
             endpoint: RwLock::new(Endpoint {
                 addr: endpoint,
-                conn: None,
+                transport: None,
             }),
             allowed_ips: allowed_ips.iter().map(|ip| (ip, ())).collect(),
             preshared_key,
@@ -83,10 +667,36 @@ impl Peer {
         self.endpoint.write()
     }
 
+    /// Sends an already-encapsulated WireGuard packet to this peer's current endpoint.
+    /// This is the single place outbound packets leave the process: it always goes
+    /// through the connected `Transport`, so SOCKS5/WebSocket/HTTP CONNECT/SOCKS4
+    /// framing happens the same way a direct UDP send would, with no special-casing
+    /// elsewhere.
+    pub fn send_to_endpoint(&self, packet: &[u8]) -> Result<(), Error> {
+        let endpoint = self.endpoint.read();
+        let transport = endpoint
+            .transport
+            .as_ref()
+            .ok_or_else(|| Error::Connect("Endpoint not connected".to_owned()))?;
+        transport.send_packet(packet)
+    }
+
+    /// Reads the next packet from this peer's current endpoint, through whichever
+    /// transport `connect_endpoint` installed. Returns `Err` wrapping `WouldBlock` when
+    /// nothing is available yet, same as reading a non-blocking UDP socket directly.
+    pub fn recv_from_endpoint(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let endpoint = self.endpoint.read();
+        let transport = endpoint
+            .transport
+            .as_ref()
+            .ok_or_else(|| Error::Connect("Endpoint not connected".to_owned()))?;
+        transport.recv_packet(buf)
+    }
+
     pub fn shutdown_endpoint(&self) {
-        if let Some(conn) = self.endpoint.write().conn.take() {
+        if let Some(transport) = self.endpoint.write().transport.take() {
             tracing::info!("Disconnecting from endpoint");
-            conn.shutdown(Shutdown::Both).unwrap();
+            transport.shutdown();
         }
     }
 
@@ -94,8 +704,8 @@ impl Peer {
         let mut endpoint = self.endpoint.write();
         if endpoint.addr != Some(addr) {
             // We only need to update the endpoint if it differs from the current one
-            if let Some(conn) = endpoint.conn.take() {
-                conn.shutdown(Shutdown::Both).unwrap();
+            if let Some(transport) = endpoint.transport.take() {
+                transport.shutdown();
             }
 
             endpoint.addr = Some(addr);
@@ -107,10 +717,10 @@ impl Peer {
         port: u16,
         fwmark: Option<u32>,
         proxy: Option<ProxyConfig>,
-    ) -> Result<socket2::Socket, Error> {
+    ) -> Result<RawFd, Error> {
         let mut endpoint = self.endpoint.write();
 
-        if endpoint.conn.is_some() {
+        if endpoint.transport.is_some() {
             return Err(Error::Connect("Connected".to_owned()));
         }
 
@@ -118,8 +728,7 @@ impl Peer {
             .addr
             .expect("Attempt to connect to undefined endpoint");
 
-        let udp_conn = if let Some(proxy_cfg) = proxy {
-            // Implement SOCKS5 UDP associate
+        let transport: Box<dyn Transport> = if let Some(proxy_cfg) = proxy {
             match proxy_cfg.proxy_type.as_str() {
                 "socks5" => {
                     tracing::info!("Connecting via SOCKS5 proxy: {}", proxy_cfg.address);
@@ -133,16 +742,53 @@ impl Peer {
                         .map_err(|e| Error::Connect(format!("Failed to connect to proxy: {}", e)))?;
                     
                     // SOCKS5 handshake
-                    // Send greeting with no authentication
-                    stream.write_all(&[0x05, 0x01, 0x00])?;
-                    
+                    // Advertise both "no authentication" and "username/password" methods;
+                    // the server picks whichever it supports.
+                    stream.write_all(&[0x05, 0x02, 0x00, 0x02])?;
+
                     // Read server response
                     let mut response = [0u8; 2];
                     stream.read_exact(&mut response)?;
-                    if response[0] != 0x05 || response[1] != 0x00 {
+                    if response[0] != 0x05 {
                         return Err(Error::Connect("SOCKS5 handshake failed".to_owned()));
                     }
-                    
+
+                    match response[1] {
+                        0x00 => {
+                            // No authentication required
+                        }
+                        0x02 => {
+                            // RFC 1929 username/password sub-negotiation
+                            let username = proxy_cfg.username.as_deref().unwrap_or("");
+                            let password = proxy_cfg.password.as_deref().unwrap_or("");
+                            if username.len() > 0xff || password.len() > 0xff {
+                                return Err(Error::Connect(
+                                    "SOCKS5 username/password must each be at most 255 bytes"
+                                        .to_owned(),
+                                ));
+                            }
+                            let mut auth_request = vec![0x01, username.len() as u8];
+                            auth_request.extend_from_slice(username.as_bytes());
+                            auth_request.push(password.len() as u8);
+                            auth_request.extend_from_slice(password.as_bytes());
+
+                            stream.write_all(&auth_request)?;
+
+                            let mut auth_response = [0u8; 2];
+                            stream.read_exact(&mut auth_response)?;
+                            if auth_response[1] != 0x00 {
+                                return Err(Error::Connect(
+                                    "SOCKS5 username/password authentication failed".to_owned(),
+                                ));
+                            }
+                        }
+                        _ => {
+                            return Err(Error::Connect(
+                                "SOCKS5 server rejected all authentication methods".to_owned(),
+                            ));
+                        }
+                    }
+
                     // Send UDP ASSOCIATE request
                     // Format: VER(1) | CMD(1) | RSV(1) | ATYP(1) | DST.ADDR(var) | DST.PORT(2)
                     // CMD = 0x03 for UDP ASSOCIATE
@@ -180,18 +826,24 @@ impl Peer {
                         _ => return Err(Error::Connect("Unsupported address type in SOCKS5 response".to_owned())),
                     };
                     
+                    // RFC 1928 section 7: a server commonly replies with BND.ADDR =
+                    // 0.0.0.0 (or ::), meaning "reuse the address of this control
+                    // connection" rather than an actual relay address.
+                    let relay_addr = if relay_addr.ip().is_unspecified() {
+                        SocketAddr::new(proxy_addr.ip(), relay_addr.port())
+                    } else {
+                        relay_addr
+                    };
+
                     tracing::info!("SOCKS5 UDP relay address: {}", relay_addr);
-                    
-                    // Close TCP connection (we don't need it anymore for UDP)
-                    drop(stream);
-                    
+
                     // Create UDP socket and connect to relay address
                     let udp_socket = socket2::Socket::new(
                         Domain::for_address(relay_addr),
-                        Type::STREAM,
+                        Type::DGRAM,
                         Some(Protocol::UDP),
                     ).map_err(|e| Error::Connect(format!("Failed to create UDP socket: {}", e)))?;
-                    
+
                     udp_socket.set_reuse_address(true)?;
                     let bind_addr = if relay_addr.is_ipv4() {
                         SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into()
@@ -200,28 +852,133 @@ impl Peer {
                     };
                     udp_socket.bind(&bind_addr)?;
                     udp_socket.connect(&relay_addr.into())?;
-                    
-                    udp_socket
+                    udp_socket.set_nonblocking(true)?;
+
+                    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                    if let Some(fwmark) = fwmark {
+                        udp_socket.set_mark(fwmark)?;
+                    }
+
+                    // Keep the control connection alive for the tunnel's lifetime: per
+                    // RFC 1928, closing it terminates the UDP association. Every
+                    // datagram sent/received on this socket must carry the SOCKS5 UDP
+                    // per-packet header addressed to the real WireGuard endpoint.
+                    Box::new(Socks5UdpTransport {
+                        socket: udp_socket,
+                        control: Mutex::new(stream),
+                        target: addr,
+                    })
+                }
+                "socks4" => {
+                    tracing::info!("Connecting via SOCKS4 proxy: {}", proxy_cfg.address);
+
+                    let proxy_addr: SocketAddr = proxy_cfg.address.parse()
+                        .map_err(|e| Error::Connect(format!("Invalid proxy address: {}", e)))?;
+
+                    let mut stream = TcpStream::connect(proxy_addr)
+                        .map_err(|e| Error::Connect(format!("Failed to connect to proxy: {}", e)))?;
+
+                    socks4_connect_handshake(&mut stream, addr, proxy_cfg.username.as_deref())?;
+
+                    stream.set_nonblocking(true)?;
+
+                    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                    if let Some(fwmark) = fwmark {
+                        SockRef::from(&stream).set_mark(fwmark)?;
+                    }
+
+                    // SOCKS4 has no native UDP-associate; reuse the same
+                    // length-prefixed TCP framing as the HTTP CONNECT transport.
+                    Box::new(TcpFramedTransport {
+                        state: Mutex::new(BufferedStream::new(stream)),
+                    })
+                }
+                "wss" => {
+                    // There's no TLS implementation wired up here; connecting would
+                    // mean sending the plaintext WebSocket handshake straight to a TLS
+                    // endpoint, which just fails. Reject explicitly rather than pretend
+                    // to support it.
+                    return Err(Error::Connect(
+                        "wss proxy requested but TLS is not supported".to_owned(),
+                    ));
+                }
+                "ws" => {
+                    tracing::info!("Connecting via WebSocket proxy: {}", proxy_cfg.address);
+
+                    let proxy_addr: SocketAddr = proxy_cfg.address.parse()
+                        .map_err(|e| Error::Connect(format!("Invalid proxy address: {}", e)))?;
+
+                    let mut stream = TcpStream::connect(proxy_addr)
+                        .map_err(|e| Error::Connect(format!("Failed to connect to proxy: {}", e)))?;
+
+                    websocket_handshake(&mut stream, &proxy_cfg.address, "/")?;
+
+                    stream.set_nonblocking(true)?;
+
+                    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                    if let Some(fwmark) = fwmark {
+                        SockRef::from(&stream).set_mark(fwmark)?;
+                    }
+
+                    Box::new(WebSocketTransport {
+                        state: Mutex::new(BufferedStream::new(stream)),
+                    })
                 }
                 "http" => {
-                    tracing::warn!("HTTP proxy not supported for UDP, using direct connection");
-                    socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::UDP))?
+                    tracing::info!("Connecting via HTTP CONNECT proxy: {}", proxy_cfg.address);
+
+                    let proxy_addr: SocketAddr = proxy_cfg.address.parse()
+                        .map_err(|e| Error::Connect(format!("Invalid proxy address: {}", e)))?;
+
+                    let mut stream = TcpStream::connect(proxy_addr)
+                        .map_err(|e| Error::Connect(format!("Failed to connect to proxy: {}", e)))?;
+
+                    http_connect_handshake(
+                        &mut stream,
+                        addr,
+                        proxy_cfg.username.as_deref(),
+                        proxy_cfg.password.as_deref(),
+                    )?;
+
+                    stream.set_nonblocking(true)?;
+
+                    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                    if let Some(fwmark) = fwmark {
+                        SockRef::from(&stream).set_mark(fwmark)?;
+                    }
+
+                    Box::new(TcpFramedTransport {
+                        state: Mutex::new(BufferedStream::new(stream)),
+                    })
                 }
                 _ => {
                     tracing::warn!("Unknown proxy type: {}, using direct connection", proxy_cfg.proxy_type);
-                    socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::UDP))?
+                    let socket = socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+                    socket.connect(&addr.into())?;
+                    socket.set_nonblocking(true)?;
+
+                    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                    if let Some(fwmark) = fwmark {
+                        socket.set_mark(fwmark)?;
+                    }
+
+                    Box::new(UdpTransport(socket))
                 }
             }
         } else {
-            socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::UDP))?
+            let socket = socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            socket.connect(&addr.into())?;
+            socket.set_nonblocking(true)?;
+
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            if let Some(fwmark) = fwmark {
+                socket.set_mark(fwmark)?;
+            }
+
+            Box::new(UdpTransport(socket))
         };
-        
-        udp_conn.set_nonblocking(true)?;
 
-        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
-        if let Some(fwmark) = fwmark {
-            udp_conn.set_mark(fwmark)?;
-        }
+        let raw_fd = transport.as_raw_fd();
 
         tracing::info!(
             message="Connected endpoint",
@@ -229,9 +986,9 @@ impl Peer {
             endpoint=?endpoint.addr.unwrap()
         );
 
-        endpoint.conn = Some(udp_conn.try_clone().unwrap());
+        endpoint.transport = Some(transport);
 
-        Ok(udp_conn)
+        Ok(raw_fd)
     }
 
     pub fn is_allowed_ip<I: Into<IpAddr>>(&self, addr: I) -> bool {
@@ -258,3 +1015,119 @@ impl Peer {
         self.index
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socks5_udp_header_roundtrip_v4() {
+        let dst: SocketAddr = "203.0.113.5:51820".parse().unwrap();
+        let header = socks5_udp_header(dst);
+        assert_eq!(header, [0x00, 0x00, 0x00, 0x01, 203, 0, 113, 5, 0xca, 0x6c]);
+
+        let mut datagram = header;
+        datagram.extend_from_slice(b"payload");
+        assert_eq!(strip_socks5_udp_header(&datagram).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn socks5_udp_header_roundtrip_v6() {
+        let dst: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let header = socks5_udp_header(dst);
+        assert_eq!(header[..4], [0x00, 0x00, 0x00, 0x04]);
+
+        let mut datagram = header;
+        datagram.extend_from_slice(b"payload");
+        assert_eq!(strip_socks5_udp_header(&datagram).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn socks5_udp_header_rejects_truncated_datagram() {
+        assert!(strip_socks5_udp_header(&[0x00, 0x00, 0x00]).is_err());
+        assert!(strip_socks5_udp_header(&[0x00, 0x00, 0x00, 0x01, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn socks5_udp_header_rejects_fragmentation_and_unknown_atyp() {
+        assert!(strip_socks5_udp_header(&[0x00, 0x00, 0x01, 0x01, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(strip_socks5_udp_header(&[0x00, 0x00, 0x00, 0x03, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn ws_frame_roundtrip() {
+        let payload = b"wireguard packet".to_vec();
+        let frame = encode_ws_frame(0x2, &payload);
+        let (consumed, parsed) = try_parse_ws_frame(&frame).unwrap().unwrap();
+        assert_eq!(consumed, frame.len());
+        assert_eq!(parsed.opcode, 0x2);
+        assert_eq!(parsed.payload, payload);
+    }
+
+    #[test]
+    fn ws_frame_waits_for_more_bytes() {
+        let frame = encode_ws_frame(0x2, b"hello");
+        assert!(try_parse_ws_frame(&frame[..frame.len() - 1])
+            .unwrap()
+            .is_none());
+        assert!(try_parse_ws_frame(&frame[..1]).unwrap().is_none());
+    }
+
+    #[test]
+    fn ws_frame_parses_ping_and_pong_as_control_opcodes() {
+        let ping = encode_ws_frame(0x9, b"keepalive");
+        let (_, parsed) = try_parse_ws_frame(&ping).unwrap().unwrap();
+        assert_eq!(parsed.opcode, 0x9);
+        assert_eq!(parsed.payload, b"keepalive");
+
+        let pong = encode_ws_frame(0xa, b"keepalive");
+        let (_, parsed) = try_parse_ws_frame(&pong).unwrap().unwrap();
+        assert_eq!(parsed.opcode, 0xa);
+    }
+
+    #[test]
+    fn ws_frame_rejects_fragmentation() {
+        let mut frame = encode_ws_frame(0x2, b"hello");
+        frame[0] &= !0x80; // clear FIN
+        assert!(try_parse_ws_frame(&frame).is_err());
+    }
+
+    #[test]
+    fn http_connect_request_without_auth() {
+        let target: SocketAddr = "10.0.0.1:51820".parse().unwrap();
+        let request = http_connect_request(target, None, None);
+        assert!(request.starts_with("CONNECT 10.0.0.1:51820 HTTP/1.1\r\n"));
+        assert!(request.contains("Host: 10.0.0.1:51820\r\n"));
+        assert!(!request.contains("Proxy-Authorization"));
+        assert!(request.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn http_connect_request_with_auth() {
+        let target: SocketAddr = "10.0.0.1:51820".parse().unwrap();
+        let request = http_connect_request(target, Some("alice"), Some("hunter2"));
+        let expected = base64::engine::general_purpose::STANDARD.encode("alice:hunter2");
+        assert!(request.contains(&format!("Proxy-Authorization: Basic {expected}\r\n")));
+    }
+
+    #[test]
+    fn socks4_request_v4_layout() {
+        let target: SocketAddr = "203.0.113.5:51820".parse().unwrap();
+        let request = socks4_connect_request(target, "alice").unwrap();
+        assert_eq!(
+            request,
+            [
+                0x04, 0x01, // VER, CMD
+                0xca, 0x6c, // port
+                203, 0, 113, 5, // IP
+                b'a', b'l', b'i', b'c', b'e', 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn socks4_request_rejects_ipv6() {
+        let target: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        assert!(socks4_connect_request(target, "").is_err());
+    }
+}